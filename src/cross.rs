@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use failure::{Error, ResultExt};
+use prebuilts;
 use sdk::{TargetOptions, sysroot_path, toolchain_path};
 use std::env;
 use std::fs;
@@ -10,6 +11,10 @@ use std::path::PathBuf;
 use std::process::Command;
 
 pub fn cross_root(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    if prebuilts::native_deps_are_fetched(target_options)? {
+        return prebuilts::fetched_native_deps_path(target_options);
+    }
+
     let home_value = env::var("HOME")?;
 
     Ok(PathBuf::from(home_value).join(".fargo").join("native_deps").join(target_options.target_cpu))
@@ -74,9 +79,12 @@ pub fn run_configure(
 
     let toolchain_bin_path = toolchain_path.join("bin");
 
+    let target_triple = format!("{}-fuchsia", target_options.target_cpu_linker);
+
     let common_c_flags = format!(
-        "--sysroot={} --target=x86_64-fuchsia -fPIC -I{}",
+        "--sysroot={} --target={} -fPIC -I{}",
         sysroot_path.to_str().unwrap(),
+        target_triple,
         cross_root.join("include").to_str().unwrap()
     );
 
@@ -90,10 +98,12 @@ pub fn run_configure(
 
     let prefix = format!("--prefix={}", cross_root_str);
 
+    let host_flag = format!("--host={}-fuchsia-elf", target_options.target_cpu_linker);
+
     let mut configure_args = vec![];
 
     if use_host {
-        configure_args.push("--host=x86_64-fuchsia-elf");
+        configure_args.push(&host_flag);
     }
 
     configure_args.push(&prefix);
@@ -0,0 +1,224 @@
+// Copyright 2017 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Boots and tears down a Fuchsia emulator (`aemu`), mirroring the fvdl
+//! host-tools model: locate the emulator and its fvm/zbi assets, boot an
+//! image, and record the resulting device name so that `fargo run`/`fargo
+//! test` can target it automatically without the user passing `-d` again.
+
+use failure::{Error, ResultExt};
+use sdk::{boot_images_dir, emulator_path, zircon_tool_path, TargetOptions};
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn emu_state_dir() -> Result<PathBuf, Error> {
+    let home_value = env::var("HOME")?;
+    Ok(PathBuf::from(home_value).join(".fargo").join("emu"))
+}
+
+fn pid_path() -> Result<PathBuf, Error> {
+    Ok(emu_state_dir()?.join("pid"))
+}
+
+fn device_name_path() -> Result<PathBuf, Error> {
+    Ok(emu_state_dir()?.join("device_name"))
+}
+
+/// Returns the device name recorded by a currently-running `fargo emu`
+/// instance, if one was started in this environment.
+pub fn recorded_device_name() -> Result<Option<String>, Error> {
+    let path = device_name_path()?;
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut contents = String::new();
+    File::open(&path)?.read_to_string(&mut contents)?;
+    Ok(Some(contents.trim().to_string()))
+}
+
+fn discover_device_name(device_finder_path: &PathBuf, verbose: bool) -> Result<String, Error> {
+    // Give the emulator a moment to bring up netstack before discovery.
+    thread::sleep(Duration::from_secs(5));
+
+    let output = Command::new(device_finder_path)
+        .arg("list")
+        .arg("-device-limit")
+        .arg("1")
+        .output()
+        .context("Unable to run device-finder")?;
+
+    if verbose {
+        println!("device-finder: {:?}", output);
+    }
+
+    if !output.status.success() {
+        bail!("device-finder did not find a booted emulator");
+    }
+
+    let device_name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if device_name.is_empty() {
+        bail!("device-finder returned no device name");
+    }
+    Ok(device_name)
+}
+
+/// Uses the `zbi` tool to copy the prebuilt `zircon-a.zbi` from
+/// `images_dir` into `emu_state_dir()/fargo.zbi`. This mirrors fvdl's use
+/// of `zbi` to *compose* the boot image before boot: `zbi` is a build-time
+/// tool, not something `aemu` itself understands, so only the composed
+/// image path is ever passed to the emulator. No boot args (serial
+/// console, autorun) are folded in yet; this is just the seam for adding
+/// them once fargo needs to.
+fn compose_boot_zbi(zbi_path: &Path, images_dir: &Path, work_dir: &Path) -> Result<PathBuf, Error> {
+    let base_zbi = images_dir.join("zircon-a.zbi");
+    let composed_zbi = work_dir.join("fargo.zbi");
+
+    let status = Command::new(zbi_path)
+        .arg("-o")
+        .arg(&composed_zbi)
+        .arg(&base_zbi)
+        .status()
+        .context("Unable to run zbi")?;
+    if !status.success() {
+        bail!("zbi failed to compose the boot image from {:?}", base_zbi);
+    }
+
+    Ok(composed_zbi)
+}
+
+/// Uses the `fvm` tool to build a storage disk image seeded with the
+/// prebuilt `blob.blk` from `images_dir`, writing the result to
+/// `emu_state_dir()/fargo.blk`. As with `zbi` above, `fvm` composes the
+/// image ahead of time; `aemu` is only ever given the resulting `.blk`
+/// file, attached as a drive, not the `fvm` tool itself.
+fn compose_fvm_image(fvm_path: &Path, images_dir: &Path, work_dir: &Path) -> Result<PathBuf, Error> {
+    let blob_image = images_dir.join("blob.blk");
+    let composed_fvm = work_dir.join("fargo.blk");
+
+    fs::remove_file(&composed_fvm).ok();
+
+    let status = Command::new(fvm_path)
+        .arg(&composed_fvm)
+        .arg("create")
+        .arg("--blob")
+        .arg(&blob_image)
+        .status()
+        .context("Unable to run fvm")?;
+    if !status.success() {
+        bail!("fvm failed to compose the disk image from {:?}", blob_image);
+    }
+
+    Ok(composed_fvm)
+}
+
+fn spawn_grpcwebproxy(verbose: bool, port: u16) -> Result<(), Error> {
+    let mut cmd = Command::new("grpcwebproxy");
+    cmd.arg("--server_http_debug_port").arg(port.to_string());
+
+    if verbose {
+        println!("grpcwebproxy: {:?}", cmd);
+    }
+
+    cmd.spawn().context("Unable to start grpcwebproxy")?;
+    Ok(())
+}
+
+/// Boots a Fuchsia emulator for `target_options`, waits for it to come up,
+/// and returns the device name it was discovered under. That name is
+/// recorded to `~/.fargo/emu` so that later `fargo run`/`fargo test`
+/// invocations pick it up automatically. `headless` suppresses the
+/// emulator's display window; `grpcwebproxy_port`, if set, additionally
+/// spawns a `grpcwebproxy` passthrough for remote display on that port.
+pub fn start_emulator(
+    verbose: bool,
+    target_options: &TargetOptions,
+    headless: bool,
+    grpcwebproxy_port: Option<u16>,
+) -> Result<String, Error> {
+    if pid_path()?.is_file() {
+        bail!("an emulator appears to already be running; run `fargo emu kill` first");
+    }
+
+    let emulator_path = emulator_path(target_options)?;
+    let device_finder_path = zircon_tool_path(target_options, "device-finder")?;
+    let fvm_path = zircon_tool_path(target_options, "fvm")?;
+    let zbi_path = zircon_tool_path(target_options, "zbi")?;
+
+    let images_dir = boot_images_dir(target_options)?;
+    let work_dir = emu_state_dir()?;
+    fs::create_dir_all(&work_dir)?;
+    let kernel_image = images_dir.join("qemu-kernel.kernel");
+    let zbi_image = compose_boot_zbi(&zbi_path, &images_dir, &work_dir)?;
+    let fvm_image = compose_fvm_image(&fvm_path, &images_dir, &work_dir)?;
+
+    let mut cmd = Command::new(&emulator_path);
+    cmd.arg("-kernel")
+        .arg(&kernel_image)
+        .arg("-initrd")
+        .arg(&zbi_image)
+        .arg("-fvm-image")
+        .arg(&fvm_image);
+
+    if headless {
+        cmd.arg("-no-window");
+    }
+
+    cmd.stdout(Stdio::null()).stderr(Stdio::null());
+
+    if verbose {
+        println!("emulator: {:?}", cmd);
+    }
+
+    let child = cmd.spawn().context("Unable to start aemu")?;
+    let pid = child.id();
+
+    File::create(pid_path()?)?.write_all(pid.to_string().as_bytes())?;
+
+    let device_name = match discover_device_name(&device_finder_path, verbose) {
+        Ok(device_name) => device_name,
+        Err(error) => {
+            kill_emulator(verbose).ok();
+            return Err(error);
+        }
+    };
+    File::create(device_name_path()?)?.write_all(device_name.as_bytes())?;
+
+    if let Some(port) = grpcwebproxy_port {
+        spawn_grpcwebproxy(verbose, port)?;
+    }
+
+    Ok(device_name)
+}
+
+/// Implements `fargo emu kill`: stops a previously-started emulator and
+/// clears its recorded state.
+pub fn kill_emulator(verbose: bool) -> Result<(), Error> {
+    let pid_file_path = pid_path()?;
+    if !pid_file_path.is_file() {
+        bail!("no emulator appears to be running (no recorded pid)");
+    }
+
+    let mut pid_str = String::new();
+    File::open(&pid_file_path)?.read_to_string(&mut pid_str)?;
+    let pid: u32 = pid_str.trim().parse().context("parsing recorded emulator pid")?;
+
+    if verbose {
+        println!("killing emulator pid {}", pid);
+    }
+
+    let status =
+        Command::new("kill").arg(pid.to_string()).status().context("Unable to run kill")?;
+    if !status.success() {
+        bail!("failed to kill emulator pid {}", pid);
+    }
+
+    fs::remove_file(&pid_file_path).ok();
+    fs::remove_file(device_name_path()?).ok();
+    Ok(())
+}
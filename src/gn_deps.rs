@@ -1,8 +1,8 @@
-use sdk::TargetOptions;
-use std::collections::HashSet;
+use sdk::{fuchsia_root, TargetOptions};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use toml;
 use toml::Value as Toml;
 
@@ -24,7 +24,12 @@ error_chain!{
 struct Manifest {
     package: Option<Package>,
     dependencies: Option<Toml>,
-    workspace: Option<Toml>,
+    #[serde(rename = "dev-dependencies")]
+    dev_dependencies: Option<Toml>,
+    #[serde(rename = "build-dependencies")]
+    build_dependencies: Option<Toml>,
+    workspace: Option<Workspace>,
+    patch: Option<Patch>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,42 +37,265 @@ struct Package {
     name: Option<String>,
 }
 
-pub fn get_dependency_names(manifest: &str) -> Result<HashSet<String>> {
-    let decoded: Manifest = toml::from_str(&manifest)?;
-    let deps = decoded.dependencies.chain_err(|| "Crate manifest had no dependencies.")?;
-    let mut dep_set = HashSet::new();
+#[derive(Debug, Deserialize)]
+struct Workspace {
+    members: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Patch {
+    #[serde(rename = "crates-io")]
+    crates_io: Option<HashMap<String, PatchEntry>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchEntry {
+    path: Option<String>,
+}
+
+/// Where a dependency's crate content comes from, as declared in its
+/// `Cargo.toml` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DependencySource {
+    /// `foo = "1.2.3"` or `foo = { version = "1.2.3" }`.
+    Registry(String),
+    /// `foo = { path = "../foo" }`.
+    Path(String),
+    /// `foo = { git = "https://..." }`.
+    Git(String),
+    /// A table-form dependency with none of `version`/`path`/`git` set
+    /// (e.g. only `features`/`optional`).
+    Unspecified,
+}
+
+/// A single entry from a `[dependencies]`/`[dev-dependencies]`/
+/// `[build-dependencies]` table.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DependencyEntry {
+    pub name: String,
+    pub source: DependencySource,
+}
+
+fn dependency_entries_from_table(deps: &Toml) -> Result<HashSet<DependencyEntry>> {
     let deps_table = match deps {
-        Toml::Table(table) => table,
+        &Toml::Table(ref table) => table,
         _ => bail!("Crate manifest dependencies not a table"),
     };
+    let mut entries = HashSet::new();
     for (key, value) in deps_table.iter() {
-        match value {
-            &Toml::String(ref _version) => {
-                dep_set.insert(key.clone());
+        let source = match value {
+            &Toml::String(ref version) => DependencySource::Registry(version.clone()),
+            &Toml::Table(ref dep_table) => {
+                if let Some(&Toml::String(ref path)) = dep_table.get("path") {
+                    DependencySource::Path(path.clone())
+                } else if let Some(&Toml::String(ref git)) = dep_table.get("git") {
+                    DependencySource::Git(git.clone())
+                } else if let Some(&Toml::String(ref version)) = dep_table.get("version") {
+                    DependencySource::Registry(version.clone())
+                } else {
+                    DependencySource::Unspecified
+                }
+            }
+            _ => bail!("Crate {} manifest has an unsupported dependency value", key),
+        };
+        entries.insert(DependencyEntry { name: key.clone(), source: source });
+    }
+    Ok(entries)
+}
+
+/// Collects the dependency entries of a crate manifest. `[dependencies]` is
+/// always included; pass `include_dev_and_build` to also fold in
+/// `[dev-dependencies]` and `[build-dependencies]`. Accepts both the plain
+/// `foo = "1.2.3"` form and the table form (`foo = { version = "1", features
+/// = [...] }`, `foo = { path = ".." }`, `foo = { git = ".." }`).
+pub fn get_dependency_names(
+    manifest: &str,
+    include_dev_and_build: bool,
+) -> Result<HashSet<DependencyEntry>> {
+    let decoded: Manifest = toml::from_str(&manifest)?;
+    let deps = decoded.dependencies.chain_err(|| "Crate manifest had no dependencies.")?;
+    let mut entries = dependency_entries_from_table(&deps)?;
+
+    if include_dev_and_build {
+        if let Some(dev_deps) = decoded.dev_dependencies {
+            entries.extend(dependency_entries_from_table(&dev_deps)?);
+        }
+        if let Some(build_deps) = decoded.build_dependencies {
+            entries.extend(dependency_entries_from_table(&build_deps)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Expands a single `[workspace].members` glob entry (e.g.
+/// `"examples/fidl/*_rust"`) into the set of matching directories under
+/// `workspace_root`. Only the single-`*`-per-segment form used by Fuchsia's
+/// workspace manifests is supported.
+fn expand_member_glob(workspace_root: &Path, member_glob: &str) -> Vec<PathBuf> {
+    let mut candidates = vec![workspace_root.to_path_buf()];
+    for segment in member_glob.split('/') {
+        let mut next_candidates = Vec::new();
+        for candidate in &candidates {
+            if segment.contains('*') {
+                let entries = match candidate.read_dir() {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for entry in entries.filter_map(|entry| entry.ok()) {
+                    let path = entry.path();
+                    let name = match path.file_name().and_then(|name| name.to_str()) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    if path.is_dir() && glob_segment_matches(segment, name) {
+                        next_candidates.push(path);
+                    }
+                }
+            } else {
+                let joined = candidate.join(segment);
+                if joined.is_dir() {
+                    next_candidates.push(joined);
+                }
             }
-            _ => bail!("Crate {} manifest has a non-string dependency", key),
         }
+        candidates = next_candidates;
     }
-    Ok(dep_set)
+    candidates
 }
 
-pub fn get_crates_with_build_files(workspace: &str) -> Result<HashSet<String>> {
+fn glob_segment_matches(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        Some(star_index) => {
+            let (prefix, rest) = pattern.split_at(star_index);
+            let suffix = &rest[1..];
+            name.len() >= prefix.len() + suffix.len() && name.starts_with(prefix)
+                && name.ends_with(suffix)
+        }
+        None => pattern == name,
+    }
+}
+
+/// Walks the `[workspace].members` globs of `workspace` (rooted at
+/// `workspace_root`) and returns the set of member crate directory names
+/// that already ship a hand-written `BUILD.gn`, and so should be skipped
+/// when regenerating GN rules.
+pub fn get_crates_with_build_files(
+    workspace_root: &Path,
+    workspace: &str,
+) -> Result<HashSet<String>> {
     let decoded: Manifest = toml::from_str(&workspace)?;
-    println!("decoded = {:?}", decoded);
-    let mut dep_set = HashSet::new();
-    Ok(dep_set)
+    let members = decoded
+        .workspace
+        .chain_err(|| "Workspace manifest had no [workspace] section")?
+        .members
+        .unwrap_or_default();
+
+    let mut crates_with_build_files = HashSet::new();
+    for member_glob in &members {
+        for member_path in expand_member_glob(workspace_root, member_glob) {
+            if !member_path.join("BUILD.gn").is_file() {
+                continue;
+            }
+            if let Some(name) = member_path.file_name().and_then(|name| name.to_str()) {
+                crates_with_build_files.insert(name.to_string());
+            }
+        }
+    }
+    Ok(crates_with_build_files)
 }
 
-pub fn list_gn_deps(target_options: &TargetOptions, crate_path: &PathBuf) -> Result<()> {
+/// Maps a crate name to the GN label of its in-tree copy, using the
+/// `[patch.crates-io]` path entries of the workspace manifest. Crates with
+/// no patch entry (third-party crates pulled straight from crates.io) fall
+/// back to the shared `third_party/rust-crates` GN target.
+fn gn_label_for_dep(dep_name: &str, patch_map: &HashMap<String, PatchEntry>) -> String {
+    match patch_map.get(dep_name).and_then(|entry| entry.path.as_ref()) {
+        Some(path) => format!("//{}:{}", path.trim_start_matches("./"), dep_name),
+        None => format!("//third_party/rust-crates:{}", dep_name),
+    }
+}
+
+/// Loads the `[patch.crates-io]` table out of the workspace's top-level
+/// `Cargo.toml`, which fargo uses as the crate-name-to-GN-path map.
+fn workspace_patch_map(workspace_root: &Path) -> Result<HashMap<String, PatchEntry>> {
+    let workspace_toml_path = workspace_root.join("Cargo.toml");
+    let mut workspace_file = File::open(&workspace_toml_path)?;
+    let mut workspace_str = String::new();
+    workspace_file.read_to_string(&mut workspace_str)?;
+    let decoded: Manifest = toml::from_str(&workspace_str)?;
+    Ok(decoded.patch.and_then(|patch| patch.crates_io).unwrap_or_default())
+}
+
+/// Renders a `rust_library`/`rust_binary` GN target for `crate_name` whose
+/// `deps` are `deps` mapped through `patch_map` to in-tree GN labels.
+fn generate_build_gn(
+    crate_name: &str,
+    is_binary: bool,
+    deps: &HashSet<DependencyEntry>,
+    patch_map: &HashMap<String, PatchEntry>,
+) -> String {
+    let target_type = if is_binary { "rust_binary" } else { "rust_library" };
+    let mut dep_labels: Vec<String> =
+        deps.iter().map(|dep| gn_label_for_dep(&dep.name, patch_map)).collect();
+    dep_labels.sort();
+
+    let mut deps_lines = String::new();
+    for label in &dep_labels {
+        deps_lines.push_str(&format!("    \"{}\",\n", label));
+    }
+
+    format!(
+        "import(\"//build/rust/{target_type}.gni\")\n\n{target_type}(\"{name}\") {{\n  deps = [\n{deps}  ]\n}}\n",
+        target_type = target_type,
+        name = crate_name,
+        deps = deps_lines
+    )
+}
+
+/// Generates the `BUILD.gn` rule for the crate at `crate_path`, skipping
+/// crates that already have a hand-written `BUILD.gn` in the workspace.
+/// Writes the rule to `crate_path/BUILD.gn` when `write` is set, otherwise
+/// prints it to stdout for diffing.
+pub fn list_gn_deps(
+    target_options: &TargetOptions,
+    crate_path: &PathBuf,
+    write: bool,
+) -> Result<()> {
     let full_path = crate_path.canonicalize()?;
-    println!("target_options = {:?}, full_path = {:?}", target_options, full_path);
     let cargo_toml_path = full_path.join("Cargo.toml");
-    println!("cargo_toml_path = {:?}", cargo_toml_path);
     let mut cargo_toml_file = File::open(cargo_toml_path)?;
     let mut toml_str = String::new();
     cargo_toml_file.read_to_string(&mut toml_str)?;
 
-    let dep_names = get_dependency_names(&toml_str)?;
+    let decoded: Manifest = toml::from_str(&toml_str)?;
+    let crate_name = decoded
+        .package
+        .and_then(|package| package.name)
+        .chain_err(|| "Crate manifest had no [package] name")?;
+
+    let workspace_root =
+        fuchsia_root(target_options).chain_err(|| "Unable to locate Fuchsia workspace root")?;
+    let workspace_toml_path = workspace_root.join("Cargo.toml");
+    let mut workspace_str = String::new();
+    File::open(&workspace_toml_path)?.read_to_string(&mut workspace_str)?;
+
+    if get_crates_with_build_files(&workspace_root, &workspace_str)?.contains(&crate_name) {
+        println!("{} already has a hand-written BUILD.gn, skipping", crate_name);
+        return Ok(());
+    }
+
+    let dep_names = get_dependency_names(&toml_str, false)?;
+    let patch_map = workspace_patch_map(&workspace_root)?;
+    let is_binary = full_path.join("src").join("main.rs").is_file();
+    let build_gn = generate_build_gn(&crate_name, is_binary, &dep_names, &patch_map);
+
+    if write {
+        let mut build_gn_file = File::create(full_path.join("BUILD.gn"))?;
+        build_gn_file.write_all(build_gn.as_bytes())?;
+    } else {
+        println!("{}", build_gn);
+    }
     Ok(())
 }
 
@@ -99,15 +327,65 @@ mod tests {
     tokio-fuchsia = "0.1.0"
     "#;
 
-    use gn_deps::{get_crates_with_build_files, get_dependency_names};
+    use gn_deps::{
+        generate_build_gn, get_crates_with_build_files, get_dependency_names, DependencyEntry,
+        DependencySource, PatchEntry,
+    };
+    use std::collections::{HashMap, HashSet};
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::Path;
 
     #[test]
     fn test_get_dependency_names() {
-        let result = get_dependency_names(FUCHSIA_APP_CONTENTS).unwrap();
+        let result = get_dependency_names(FUCHSIA_APP_CONTENTS, false).unwrap();
         println!("result = {:?}", result);
         assert_eq!(10, result.len());
     }
 
+    static TABLE_FORM_CONTENTS: &'static str = r#"
+    [package]
+    name = "fargo-example"
+    version = "0.1.0"
+
+    [dependencies]
+    clap = "2"
+    failure = { version = "0.1" }
+    fuchsia-zircon = { path = "../fuchsia-zircon" }
+    tokio-core = { git = "https://fuchsia.googlesource.com/third_party/rust-mirrors/tokio-core" }
+
+    [dev-dependencies]
+    tempdir = "0.3"
+
+    [build-dependencies]
+    cc = "1.0"
+    "#;
+
+    #[test]
+    fn test_get_dependency_names_table_form() {
+        let result = get_dependency_names(TABLE_FORM_CONTENTS, false).unwrap();
+        assert_eq!(4, result.len());
+        let fuchsia_zircon =
+            result.iter().find(|entry| entry.name == "fuchsia-zircon").unwrap();
+        assert_eq!(
+            DependencySource::Path("../fuchsia-zircon".to_string()),
+            fuchsia_zircon.source
+        );
+        let tokio_core = result.iter().find(|entry| entry.name == "tokio-core").unwrap();
+        match tokio_core.source {
+            DependencySource::Git(_) => (),
+            ref other => panic!("expected a git dependency, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_get_dependency_names_dev_and_build() {
+        let result = get_dependency_names(TABLE_FORM_CONTENTS, true).unwrap();
+        assert_eq!(6, result.len());
+        assert!(result.iter().any(|entry| entry.name == "tempdir"));
+        assert!(result.iter().any(|entry| entry.name == "cc"));
+    }
+
     static WORKSPACE_CONTENTS: &'static str = r#"
     [workspace]
     members =  [
@@ -150,8 +428,82 @@ mod tests {
 
     #[test]
     fn test_get_crates_with_build_files() {
-        let result = get_crates_with_build_files(WORKSPACE_CONTENTS).unwrap();
-        println!("result = {:?}", result);
+        // No member directories exist on disk under this made-up root, so
+        // nothing should be reported as already having a BUILD.gn.
+        let result =
+            get_crates_with_build_files(Path::new("/nonexistent-fargo-test-root"), WORKSPACE_CONTENTS)
+                .unwrap();
+        assert_eq!(0, result.len());
     }
 
+    #[test]
+    fn test_get_crates_with_build_files_finds_existing_build_gn() {
+        let root = env::temp_dir().join("fargo-test-get-crates-with-build-files");
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("has-build-gn")).unwrap();
+        fs::create_dir_all(root.join("no-build-gn")).unwrap();
+        File::create(root.join("has-build-gn").join("BUILD.gn")).unwrap();
+
+        let workspace = r#"
+        [workspace]
+        members = [
+          "has-build-gn",
+          "no-build-gn",
+        ]
+        "#;
+
+        let result = get_crates_with_build_files(&root, workspace).unwrap();
+        fs::remove_dir_all(&root).ok();
+
+        assert_eq!(1, result.len());
+        assert!(result.contains("has-build-gn"));
+    }
+
+    #[test]
+    fn test_get_crates_with_build_files_expands_member_glob() {
+        let root = env::temp_dir().join("fargo-test-get-crates-with-build-files-glob");
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("examples/fidl/echo_rust")).unwrap();
+        fs::create_dir_all(root.join("examples/fidl/echo_cpp")).unwrap();
+        File::create(root.join("examples/fidl/echo_rust").join("BUILD.gn")).unwrap();
+
+        let workspace = r#"
+        [workspace]
+        members = [
+          "examples/fidl/*_rust",
+        ]
+        "#;
+
+        let result = get_crates_with_build_files(&root, workspace).unwrap();
+        fs::remove_dir_all(&root).ok();
+
+        assert_eq!(1, result.len());
+        assert!(result.contains("echo_rust"));
+    }
+
+    #[test]
+    fn test_generate_build_gn() {
+        let mut patch_map = HashMap::new();
+        patch_map.insert(
+            "fdio".to_string(),
+            PatchEntry { path: Some("public/rust/crates/fdio".to_string()) },
+        );
+
+        let mut deps = HashSet::new();
+        deps.insert(DependencyEntry {
+            name: "fdio".to_string(),
+            source: DependencySource::Registry("0.2.0".to_string()),
+        });
+        deps.insert(DependencyEntry {
+            name: "futures".to_string(),
+            source: DependencySource::Registry("0.1.15".to_string()),
+        });
+
+        let build_gn = generate_build_gn("fuchsia-app", false, &deps, &patch_map);
+
+        assert!(build_gn.contains("import(\"//build/rust/rust_library.gni\")"));
+        assert!(build_gn.contains("rust_library(\"fuchsia-app\")"));
+        assert!(build_gn.contains("\"//public/rust/crates/fdio:fdio\",\n"));
+        assert!(build_gn.contains("\"//third_party/rust-crates:futures\",\n"));
+    }
 }
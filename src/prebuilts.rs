@@ -0,0 +1,270 @@
+// Copyright 2017 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Fetches prebuilt host tools (the clang toolchain, native cross
+//! dependencies, the Fuchsia SDK itself) from CIPD so that users don't have
+//! to populate `~/.fargo` by hand before they can build. This mirrors how
+//! fvdl resolves a data dir under `$HOME/.fuchsia` or `FUCHSIA_SDK_DATA_DIR`
+//! and fetches aemu/pm/far/zbi from CIPD.
+
+use failure::{Error, ResultExt};
+use sdk::TargetOptions;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use toml;
+use utils::is_mac;
+
+/// A single entry in a prebuilts manifest: a CIPD package ref and version to
+/// fetch, the path (relative to `prebuilts_root`) to unpack it into, and the
+/// sha256 digest the downloaded instance must match. CIPD instance IDs are
+/// themselves content digests, so this is what lets `fetch_entry` tell an
+/// interrupted or tampered download from a good one before it's trusted.
+#[derive(Debug, Deserialize)]
+pub struct PrebuiltEntry {
+    pub package: String,
+    pub version: String,
+    pub dest: String,
+    pub sha256: String,
+}
+
+/// A TOML document's root is always a table, so a manifest's `[[entry]]`
+/// array-of-tables has to be wrapped like this to deserialize; a bare
+/// `Vec<PrebuiltEntry>` can never parse.
+#[derive(Debug, Deserialize)]
+struct PrebuiltManifest {
+    entry: Vec<PrebuiltEntry>,
+}
+
+/// Root directory under which fargo caches fetched prebuilts.
+pub fn prebuilts_root() -> Result<PathBuf, Error> {
+    let home_value = env::var("HOME")?;
+    Ok(PathBuf::from(home_value).join(".fargo"))
+}
+
+/// Where `fetch-toolchain` unpacks the clang toolchain, and where
+/// `toolchain_path` looks first before falling back to an in-tree or SDK
+/// layout.
+pub fn fetched_toolchain_path() -> Result<PathBuf, Error> {
+    Ok(prebuilts_root()?.join("clang"))
+}
+
+/// Where `fetch-sdk` unpacks native cross dependencies for `target_cpu`, and
+/// where `cross_root` looks first before falling back to the legacy
+/// hand-populated `native_deps` directory.
+pub fn fetched_native_deps_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    Ok(prebuilts_root()?.join("fetched_native_deps").join(target_options.target_cpu))
+}
+
+fn stamp_path(dest: &Path) -> PathBuf {
+    dest.join(".cipd_version")
+}
+
+fn is_up_to_date(dest: &Path, version: &str) -> bool {
+    let mut contents = String::new();
+    match File::open(stamp_path(dest)).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => contents.trim() == version,
+        Err(_) => false,
+    }
+}
+
+/// A prebuilt only counts as fetched once it carries a `.cipd_version`
+/// stamp, which `fetch_entry` only writes after a successful,
+/// integrity-checked download. Bare directory existence is not enough: a
+/// failed or interrupted fetch must not shadow a working in-tree/SDK
+/// toolchain.
+fn is_fetched(dest: &Path) -> bool {
+    dest.is_dir() && stamp_path(dest).is_file()
+}
+
+/// True once `fetch-toolchain` has successfully populated
+/// `fetched_toolchain_path()`.
+pub fn toolchain_is_fetched() -> Result<bool, Error> {
+    Ok(is_fetched(&fetched_toolchain_path()?))
+}
+
+/// True once `fetch-sdk` has successfully populated
+/// `fetched_native_deps_path(target_options)`.
+pub fn native_deps_are_fetched(target_options: &TargetOptions) -> Result<bool, Error> {
+    Ok(is_fetched(&fetched_native_deps_path(target_options)?))
+}
+
+/// Computes the lowercase hex sha256 digest of the file at `path`, shelling
+/// out to `shasum` on macOS and `sha256sum` on Linux.
+fn sha256_hex(path: &Path) -> Result<String, Error> {
+    let output = if is_mac() {
+        Command::new("shasum").arg("-a").arg("256").arg(path).output()
+    } else {
+        Command::new("sha256sum").arg(path).output()
+    }.context("Unable to compute sha256 digest")?;
+
+    if !output.status.success() {
+        bail!("failed to compute sha256 digest of {:?}", path);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let digest = stdout.split_whitespace().next().unwrap_or("");
+    if digest.is_empty() {
+        bail!("could not parse sha256 digest of {:?}", path);
+    }
+    Ok(digest.to_string())
+}
+
+/// Downloads and unpacks a single CIPD package instance into
+/// `prebuilts_root()/entry.dest`.
+///
+/// The download is unpacked into a staging directory next to `dest` and only
+/// `fs::rename`d into place (and stamped with `.cipd_version`) once the
+/// download's sha256 digest has been checked against `entry.sha256` and the
+/// archive has been unpacked successfully. This keeps a failed or
+/// interrupted fetch from ever leaving a half-populated `dest` behind for
+/// `toolchain_path`/`cross_root` to pick up.
+fn fetch_entry(verbose: bool, entry: &PrebuiltEntry) -> Result<(), Error> {
+    let dest = prebuilts_root()?.join(&entry.dest);
+
+    if is_up_to_date(&dest, &entry.version) {
+        if verbose {
+            println!("{} already at {}, skipping", entry.package, entry.version);
+        }
+        return Ok(());
+    }
+
+    if verbose {
+        println!("fetching {} @ {} -> {:?}", entry.package, entry.version, dest);
+    }
+
+    let dest_file_name = match dest.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => bail!("prebuilt destination {:?} has no file name", dest),
+    };
+    let staging_parent = match dest.parent() {
+        Some(parent) => parent.to_path_buf(),
+        None => bail!("prebuilt destination {:?} has no parent directory", dest),
+    };
+    let staging_dir = staging_parent.join(format!(".{}.partial", dest_file_name));
+
+    // Clean up any staging directory left behind by a previous interrupted
+    // fetch before starting a new one.
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir).context("removing stale staging directory")?;
+    }
+    fs::create_dir_all(&staging_dir).context("creating prebuilt staging directory")?;
+
+    let url = format!(
+        "https://chrome-infra-packages.appspot.com/dl/{}/+/{}",
+        entry.package, entry.version
+    );
+    let archive_path = staging_dir.join("instance.zip");
+
+    let status = Command::new("curl")
+        .arg("-L")
+        .arg("-f")
+        .arg("-o")
+        .arg(&archive_path)
+        .arg(&url)
+        .status()
+        .context("Unable to run curl")?;
+    if !status.success() {
+        fs::remove_dir_all(&staging_dir).ok();
+        bail!("failed to download {} @ {} from {}", entry.package, entry.version, url);
+    }
+
+    let actual_digest = sha256_hex(&archive_path)?;
+    if !actual_digest.eq_ignore_ascii_case(&entry.sha256) {
+        fs::remove_dir_all(&staging_dir).ok();
+        bail!(
+            "integrity check failed for {} @ {}: expected sha256 {}, got {}",
+            entry.package,
+            entry.version,
+            entry.sha256,
+            actual_digest
+        );
+    }
+
+    let status = Command::new("unzip")
+        .arg("-o")
+        .arg("-q")
+        .arg(&archive_path)
+        .arg("-d")
+        .arg(&staging_dir)
+        .status()
+        .context("Unable to run unzip")?;
+    if !status.success() {
+        fs::remove_dir_all(&staging_dir).ok();
+        bail!("failed to unpack CIPD instance for {} into {:?}", entry.package, staging_dir);
+    }
+
+    fs::remove_file(&archive_path).ok();
+
+    let mut stamp_file =
+        File::create(stamp_path(&staging_dir)).context("writing .cipd_version stamp")?;
+    stamp_file.write_all(entry.version.as_bytes())?;
+    drop(stamp_file);
+
+    if dest.is_dir() {
+        fs::remove_dir_all(&dest).context("removing previous prebuilt destination")?;
+    }
+    fs::rename(&staging_dir, &dest).context("moving fetched prebuilt into place")?;
+
+    Ok(())
+}
+
+fn fetch_manifest(verbose: bool, manifest_path: &Path) -> Result<(), Error> {
+    let mut manifest_file =
+        File::open(manifest_path).context("opening prebuilts manifest")?;
+    let mut manifest_str = String::new();
+    manifest_file.read_to_string(&mut manifest_str).context("reading prebuilts manifest")?;
+    let manifest: PrebuiltManifest =
+        toml::from_str(&manifest_str).context("parsing prebuilts manifest")?;
+    for entry in &manifest.entry {
+        fetch_entry(verbose, entry)?;
+    }
+    Ok(())
+}
+
+/// Implements `fargo fetch-toolchain`: fetches the clang toolchain CIPD
+/// packages listed in `manifest_path`, each into `prebuilts_root()/<dest>`.
+/// Only an entry whose `dest` is `clang` lands in `fetched_toolchain_path()`,
+/// where `toolchain_path` looks for it.
+pub fn fetch_toolchain(verbose: bool, manifest_path: &Path) -> Result<(), Error> {
+    fetch_manifest(verbose, manifest_path)
+}
+
+/// Implements `fargo fetch-sdk`: fetches the Fuchsia SDK and/or native
+/// cross-dependency CIPD packages listed in `manifest_path`.
+pub fn fetch_sdk(verbose: bool, manifest_path: &Path) -> Result<(), Error> {
+    fetch_manifest(verbose, manifest_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use prebuilts::PrebuiltManifest;
+    use toml;
+
+    static MANIFEST_CONTENTS: &'static str = r#"
+    [[entry]]
+    package = "fuchsia/clang/mac-amd64"
+    version = "git_revision:abcdef"
+    dest = "clang"
+    sha256 = "deadbeef"
+
+    [[entry]]
+    package = "fuchsia/clang/linux-amd64"
+    version = "git_revision:abcdef"
+    dest = "clang"
+    sha256 = "cafef00d"
+    "#;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest: PrebuiltManifest = toml::from_str(MANIFEST_CONTENTS).unwrap();
+        assert_eq!(2, manifest.entry.len());
+        assert_eq!("fuchsia/clang/mac-amd64", manifest.entry[0].package);
+        assert_eq!("git_revision:abcdef", manifest.entry[0].version);
+        assert_eq!("clang", manifest.entry[0].dest);
+        assert_eq!("cafef00d", manifest.entry[1].sha256);
+    }
+}
@@ -3,26 +3,60 @@
 // found in the LICENSE file.
 
 use failure::Error;
+use prebuilts;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 use utils::is_mac;
 
+/// Where the path-resolving functions in this module should look for the
+/// Fuchsia toolchain, sysroot, and supporting tools.
+#[derive(Debug, Clone)]
+pub enum SdkLayout {
+    /// A full Fuchsia source checkout (the traditional `FUCHSIA_ROOT` layout),
+    /// with `out/`, `buildtools/`, `scripts/fx`, and so on.
+    InTree,
+    /// A packaged Fuchsia SDK, laid out the way the GN `fuchsia_download_sdk`
+    /// template produces it: `sdk_root/arch/<cpu>/sysroot`,
+    /// `sdk_root/tools/<cpu>/...`, plus FIDL/BUILD metadata.
+    Sdk(PathBuf),
+}
+
+// Note: the layout lives on `TargetOptions` rather than `FuchsiaConfig`.
+// `FuchsiaConfig` is only parsed once a Fuchsia root/SDK root has already
+// been located (it reads `<root>/.config`), while every path-resolving
+// function below — including `fuchsia_root` itself — needs to know the
+// layout to find that root in the first place. `TargetOptions` is already
+// threaded through all of them, so that's where the dispatch key lives.
+impl SdkLayout {
+    /// Determines which layout to use from the environment. `FUCHSIA_SDK`, if
+    /// set, selects `Sdk` and points at the SDK root; otherwise falls back to
+    /// `InTree`. A `--sdk` CLI flag can override this by constructing a
+    /// `TargetOptions` with `new_with_layout` directly.
+    pub fn from_env() -> SdkLayout {
+        match env::var("FUCHSIA_SDK") {
+            Ok(sdk_root) => SdkLayout::Sdk(PathBuf::from(sdk_root)),
+            Err(_) => SdkLayout::InTree,
+        }
+    }
+}
+
 /// The `TargetOptions` struct bundles together a number of parameters specific to
-/// the Fuchsia target that need to be passed through various internal functions. For
-/// the moment there is no way to set anything but the `release_os` field, but this
-/// will change when fargo starts supporting ARM targets.
+/// the Fuchsia target that need to be passed through various internal functions,
+/// including which CPU architecture (`"x64"` or `"arm64"`) is being targeted and
+/// whether paths should be resolved against an in-tree checkout or a packaged SDK.
 #[derive(Debug)]
 pub struct TargetOptions<'a> {
     pub release_os: bool,
     pub target_cpu: &'a str,
     pub target_cpu_linker: &'a str,
     pub device_name: Option<&'a str>,
+    pub sdk_layout: SdkLayout,
 }
 
 impl<'a> TargetOptions<'a> {
-    /// Constructs a new `TargetOptions`.
+    /// Constructs a new `TargetOptions` targeting x64, the default architecture.
     ///
     /// # Examples
     ///
@@ -32,17 +66,70 @@ impl<'a> TargetOptions<'a> {
     /// let target_options = TargetOptions::new(true, Some("ivy-donut-grew-stoop"));
     /// ```
 
-    pub fn new(release_os: bool, device_name: Option<&'a str>) -> TargetOptions {
-        TargetOptions {
+    pub fn new(release_os: bool, device_name: Option<&'a str>) -> TargetOptions<'a> {
+        TargetOptions::new_with_cpu(release_os, "x64", device_name)
+            .expect("\"x64\" is always a supported target_cpu")
+    }
+
+    /// Constructs a new `TargetOptions` for the given `target_cpu`, which must be
+    /// either `"x64"` or `"arm64"`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fargo::TargetOptions;
+    ///
+    /// let target_options =
+    ///     TargetOptions::new_with_cpu(true, "arm64", Some("ivy-donut-grew-stoop")).unwrap();
+    /// ```
+
+    pub fn new_with_cpu(
+        release_os: bool,
+        target_cpu: &'a str,
+        device_name: Option<&'a str>,
+    ) -> Result<TargetOptions<'a>, Error> {
+        TargetOptions::new_with_layout(release_os, target_cpu, device_name, SdkLayout::from_env())
+    }
+
+    /// Constructs a new `TargetOptions` for the given `target_cpu`, resolving
+    /// paths against the given `sdk_layout` rather than auto-detecting it from
+    /// the environment. This is how a `--sdk` CLI flag would select out-of-tree
+    /// mode explicitly instead of relying on `FUCHSIA_SDK`.
+    ///
+    /// Bails if `target_cpu` is anything other than `"x64"` or `"arm64"`: the
+    /// path resolvers elsewhere in this module each special-case one of those
+    /// two strings and silently assume the other one otherwise, so an
+    /// unrecognized `target_cpu` has to be rejected here rather than being
+    /// allowed to quietly mismatch a linker triple against a sysroot path.
+    pub fn new_with_layout(
+        release_os: bool,
+        target_cpu: &'a str,
+        device_name: Option<&'a str>,
+        sdk_layout: SdkLayout,
+    ) -> Result<TargetOptions<'a>, Error> {
+        let target_cpu_linker = match target_cpu {
+            "x64" => "x86_64",
+            "arm64" => "aarch64",
+            _ => bail!(
+                "unsupported target_cpu {:?}; fargo only supports \"x64\" and \"arm64\"",
+                target_cpu
+            ),
+        };
+        Ok(TargetOptions {
             release_os: release_os,
-            target_cpu: "x64",
-            target_cpu_linker: "x86_64",
+            target_cpu: target_cpu,
+            target_cpu_linker: target_cpu_linker,
             device_name: device_name,
-        }
+            sdk_layout: sdk_layout,
+        })
     }
 }
 
 pub fn fuchsia_root(options: &TargetOptions) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(ref sdk_root) = options.sdk_layout {
+        return Ok(sdk_root.clone());
+    }
+
     let fuchsia_root_value = if let Ok(fuchsia_root_value) = env::var("FUCHSIA_ROOT") {
         let fuchsia_root_path = PathBuf::from(&fuchsia_root_value);
         if !fuchsia_root_path.is_dir() {
@@ -77,6 +164,12 @@ pub fn possible_target_out_dir(
     fuchsia_root: &PathBuf,
     options: &TargetOptions,
 ) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(_) = options.sdk_layout {
+        bail!(
+            "there is no Fuchsia build out directory when running against a packaged Fuchsia \
+            SDK (FUCHSIA_SDK); use sysroot_path/toolchain_path/boot_images_dir instead"
+        );
+    }
     let out_dir_name_prefix = if options.release_os { "release" } else { "debug" };
     let out_dir_name = format!("{}-{}", out_dir_name_prefix, options.target_cpu);
     let target_out_dir = fuchsia_root.join("out").join(out_dir_name);
@@ -97,22 +190,52 @@ pub fn target_gen_dir(options: &TargetOptions) -> Result<PathBuf, Error> {
 }
 
 pub fn cargo_out_dir(options: &TargetOptions) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(_) = options.sdk_layout {
+        bail!(
+            "there is no garnet/target cargo output directory when running against a packaged \
+            Fuchsia SDK (FUCHSIA_SDK)"
+        );
+    }
     let fuchsia_root = fuchsia_root(options)?;
     let target_triple = format!("{}-unknown-fuchsia", options.target_cpu_linker);
     Ok(fuchsia_root.join("garnet").join("target").join(target_triple).join("debug"))
 }
 
+/// Resolves the directory containing the prebuilt Fuchsia boot images
+/// (`qemu-kernel.kernel`, boot `.zbi`, `blob.blk`) used to start the
+/// emulator: the in-tree build's target out directory, or
+/// `sdk_root/images/<cpu>` in SDK mode.
+pub fn boot_images_dir(options: &TargetOptions) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(ref sdk_root) = options.sdk_layout {
+        return Ok(sdk_root.join("images").join(options.target_cpu));
+    }
+    target_out_dir(options)
+}
+
 pub fn strip_tool_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
     Ok(toolchain_path(target_options)?.join("bin/llvm-objcopy"))
 }
 
 pub fn sysroot_path(options: &TargetOptions) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(ref sdk_root) = options.sdk_layout {
+        return Ok(sdk_root.join("arch").join(options.target_cpu).join("sysroot"));
+    }
     let zircon_name =
         if options.target_cpu == "x64" { "build-user-x86-64" } else { "build-user-arm64" };
     Ok(fuchsia_root(&options)?.join("out").join("build-zircon").join(zircon_name).join("sysroot"))
 }
 
+/// Resolves the directory containing the clang toolchain (`bin/clang`,
+/// `bin/llvm-ar`, ...). In SDK mode this lives flatly under `sdk_root/tools`,
+/// the same layout `zircon_tool_path` assumes for the other packaged host
+/// tools, rather than being split out per `target_cpu`.
 pub fn toolchain_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    if prebuilts::toolchain_is_fetched()? {
+        return prebuilts::fetched_toolchain_path();
+    }
+    if let SdkLayout::Sdk(ref sdk_root) = target_options.sdk_layout {
+        return Ok(sdk_root.join("tools").join("clang"));
+    }
     let platform_name = if is_mac() { "mac-x64" } else { "linux-x64" };
     Ok(fuchsia_root(target_options)?.join("buildtools").join(platform_name).join("clang"))
 }
@@ -138,10 +261,30 @@ pub fn clang_ranlib_path(target_options: &TargetOptions) -> Result<PathBuf, Erro
 }
 
 pub fn fx_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(_) = target_options.sdk_layout {
+        bail!("fx is not available when running against a packaged Fuchsia SDK (FUCHSIA_SDK)");
+    }
     let fuchsia_root = fuchsia_root(target_options)?;
     Ok(fuchsia_root.join("scripts/fx"))
 }
 
+/// Resolves the path to one of the zircon host tools (`netaddr`, `netcp`,
+/// `netruncmd`, `fvm`, `zbi`, `device-finder`, ...) used to discover and
+/// talk to a running Fuchsia target.
+pub fn zircon_tool_path(target_options: &TargetOptions, tool: &str) -> Result<PathBuf, Error> {
+    if let SdkLayout::Sdk(ref sdk_root) = target_options.sdk_layout {
+        return Ok(sdk_root.join("tools").join(tool));
+    }
+    Ok(fuchsia_root(target_options)?.join("out").join("build-zircon").join("tools").join(tool))
+}
+
+/// Resolves the path to the `aemu` emulator binary matching `target_cpu`
+/// (`aemu` for x64, `aemu_arm64` for arm64).
+pub fn emulator_path(target_options: &TargetOptions) -> Result<PathBuf, Error> {
+    let emulator_name = if target_options.target_cpu == "arm64" { "aemu_arm64" } else { "aemu" };
+    zircon_tool_path(target_options, emulator_name)
+}
+
 #[derive(Debug)]
 pub struct FuchsiaConfig {
     pub fuchsia_build_dir: String,
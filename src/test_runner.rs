@@ -0,0 +1,180 @@
+// Copyright 2017 The Fuchsia Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Runs a cross-compiled Rust test binary on a Fuchsia target, the way `cargo
+//! test` would run it locally: push the binary over, launch it, stream back
+//! its output, and propagate its exit code so that exit 0 still means pass.
+
+/// Marker `run_test` appends to the remote command line so it can recover
+/// the test binary's own exit code from `netruncmd`'s stdout. `netruncmd`'s
+/// own exit status only reflects whether it managed to dispatch the
+/// command, not whether the remote process it ran passed or failed, so the
+/// remote side is made to report its exit code explicitly.
+const EXIT_SENTINEL: &str = "FARGO_EXIT=";
+
+use emu;
+use failure::{Error, ResultExt};
+use sdk::{strip_tool_path, zircon_tool_path, TargetOptions};
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Resolves the Fuchsia device to run against: the one explicitly named by
+/// `target_options.device_name`, else the one recorded by a running `fargo
+/// emu`, else the sole device discoverable on the local network via
+/// `netaddr`.
+fn resolve_device_name(target_options: &TargetOptions) -> Result<String, Error> {
+    if let Some(device_name) = target_options.device_name {
+        return Ok(device_name.to_string());
+    }
+
+    if let Some(device_name) = emu::recorded_device_name()? {
+        return Ok(device_name);
+    }
+
+    let netaddr_path = zircon_tool_path(target_options, "netaddr")?;
+    let output = Command::new(netaddr_path)
+        .arg("--nowait")
+        .arg("--timeout=1000")
+        .output()
+        .context("Unable to run netaddr")?;
+    if !output.status.success() {
+        bail!("no Fuchsia device found on the local network; pass a device name explicitly");
+    }
+    let device_name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if device_name.is_empty() {
+        bail!("netaddr found no device address");
+    }
+    Ok(device_name)
+}
+
+/// Copies `local_path` to `remote_path` on `device_name` using `netcp`.
+fn push_file(
+    target_options: &TargetOptions,
+    device_name: &str,
+    local_path: &Path,
+    remote_path: &str,
+) -> Result<(), Error> {
+    let netcp_path = zircon_tool_path(target_options, "netcp")?;
+    let destination = format!("{}:{}", device_name, remote_path);
+    let status = Command::new(netcp_path)
+        .arg(local_path)
+        .arg(&destination)
+        .status()
+        .context("Unable to run netcp")?;
+    if !status.success() {
+        bail!("failed to copy {:?} to {}", local_path, destination);
+    }
+    Ok(())
+}
+
+/// Runs `test_binary` (a built Rust test ELF) on the Fuchsia target selected
+/// by `target_options`, passing `test_args` through to it.
+///
+/// The binary is stripped with `llvm-objcopy` (mirroring the release
+/// packaging step), pushed to a scratch directory on the device with
+/// `netcp`, and launched with `netruncmd` inside a namespace rooted so that
+/// `/pkg` resolves to that scratch directory, mirroring the namespace the
+/// gtest component runner sets up. Stdout/stderr are streamed back as they
+/// arrive; the remote command reports its own exit code via the
+/// `FARGO_EXIT=` sentinel (see `EXIT_SENTINEL`) rather than `netruncmd`'s,
+/// so that `cargo test` semantics (exit 0 = pass) are preserved.
+pub fn run_test(
+    verbose: bool,
+    target_options: &TargetOptions,
+    test_binary: &Path,
+    test_args: &[&str],
+) -> Result<i32, Error> {
+    let device_name = resolve_device_name(target_options)?;
+
+    let binary_name = match test_binary.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => bail!("test binary path {:?} has no file name", test_binary),
+    };
+
+    let strip_tool_path = strip_tool_path(target_options)?;
+    let stripped_path = env::temp_dir().join(binary_name);
+    let status = Command::new(&strip_tool_path)
+        .arg("--strip-all")
+        .arg(test_binary)
+        .arg(&stripped_path)
+        .status()
+        .context("Unable to run llvm-objcopy")?;
+    if !status.success() {
+        bail!("failed to strip {:?}", test_binary);
+    }
+
+    let remote_dir = "/tmp/fargo_test";
+    let remote_path = format!("{}/{}", remote_dir, binary_name);
+    push_file(target_options, &device_name, &stripped_path, &remote_path)?;
+
+    let mut remote_command =
+        format!("{} --namespace=/pkg={}", remote_path, remote_dir);
+    for arg in test_args {
+        remote_command.push(' ');
+        remote_command.push_str(arg);
+    }
+    remote_command.push_str(&format!("; echo {}$?", EXIT_SENTINEL));
+
+    if verbose {
+        println!("netruncmd: {} {}", device_name, remote_command);
+    }
+
+    let netruncmd_path = zircon_tool_path(target_options, "netruncmd")?;
+    let mut child = Command::new(netruncmd_path)
+        .arg(&device_name)
+        .arg(&remote_command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Unable to run netruncmd")?;
+
+    // Drain stdout on its own thread so a chatty stderr (or vice versa)
+    // can't fill its pipe buffer and deadlock the child against us while we
+    // sit blocked reading the other stream. The thread also scans for the
+    // `FARGO_EXIT=` sentinel appended to the remote command, since
+    // `netruncmd`'s own exit status only reflects whether it dispatched the
+    // command, not whether the remote test passed.
+    let stdout_reader = child.stdout.take().map(|stdout| {
+        thread::spawn(move || -> Result<i32, Error> {
+            let mut exit_code = None;
+            for line in BufReader::new(stdout).lines() {
+                let line = line.context("reading test stdout")?;
+                let trimmed = line.trim();
+                if trimmed.starts_with(EXIT_SENTINEL) {
+                    exit_code = trimmed[EXIT_SENTINEL.len()..].parse().ok();
+                } else {
+                    println!("{}", line);
+                }
+            }
+            match exit_code {
+                Some(exit_code) => Ok(exit_code),
+                None => bail!("remote test never reported a {} exit code", EXIT_SENTINEL),
+            }
+        })
+    });
+
+    if let Some(stderr) = child.stderr.take() {
+        for line in BufReader::new(stderr).lines() {
+            eprintln!("{}", line.context("reading test stderr")?);
+        }
+    }
+
+    let exit_code = match stdout_reader {
+        Some(stdout_reader) => match stdout_reader.join() {
+            Ok(result) => result?,
+            Err(_) => bail!("stdout reader thread panicked"),
+        },
+        None => bail!("netruncmd's stdout was not captured"),
+    };
+
+    let status = child.wait().context("Unable to wait on netruncmd")?;
+    if !status.success() {
+        bail!("netruncmd failed to dispatch the test to the target");
+    }
+
+    Ok(exit_code)
+}